@@ -1,5 +1,7 @@
-/// Basic tree-based calculator.
-/// Author: Willmo3
+//! Basic tree-based calculator.
+//! Author: Willmo3
+
+use std::collections::{BTreeMap, HashMap};
 
 use serde::{Deserialize, Serialize};
 
@@ -10,7 +12,8 @@ use serde::{Deserialize, Serialize};
 /// # Serialization
 /// This supports serde serialization, deserialization out of the box.
 /// You specify which targets!
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// JSON and bincode helpers are available with the `json` and `bincode` features.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum Token {
     Number { value: f64 },
     Add { left: Box<Token>, right: Box<Token> },
@@ -19,28 +22,853 @@ pub enum Token {
     Divide { left: Box<Token>, right: Box<Token> },
 }
 
+/// # Description
+/// How evaluation treats division by zero.
+/// Error panics, as evaluate always has.
+/// Ieee performs the raw float division, so 1 / 0 is inf and 0 / 0 is NaN.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum DivByZero {
+    #[default]
+    Error,
+    Ieee,
+}
+
+/// # Description
+/// The binary operators a Token can apply.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+/// # Description
+/// Which operand to descend into when following a path through an AST.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Branch {
+    Left,
+    Right,
+}
+
+/// # Description
+/// Hooks for walking an AST.
+/// enter fires before a node's children are visited, exit after.
+/// Both default to doing nothing, so visitors only implement what they need.
+pub trait AstVisitor {
+    fn enter(&mut self, _token: &Token) {}
+    fn exit(&mut self, _token: &Token) {}
+}
+
 impl Token {
     /// Evaluate the AST rooted at self.     
     /// Return f64 result of computation.
     pub fn evaluate(&self) -> f64 {
-        match self {
+        self.evaluate_with(DivByZero::Error)
+    }
+
+    /// Evaluate the AST rooted at self,
+    /// handling division by zero according to div_by_zero.
+    pub fn evaluate_with(&self, div_by_zero: DivByZero) -> f64 {
+        self.eval_by(div_by_zero, &mut |_, value| Ok(value))
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Evaluate the AST rooted at self using integer arithmetic.
+    /// Every operation result is truncated toward zero,
+    /// so division is integer division: 7 / 2 is 3.
+    /// Literals are used as given, so 2.5 * 3.9 is trunc(9.75) = 9.
+    /// Panics on division by zero, as evaluate does.
+    pub fn evaluate_integer(&self) -> f64 {
+        self.eval_by(DivByZero::Error, &mut |token, value| {
+            if let Token::Number { .. } = token {
+                return Ok(value);
+            }
+            Ok(value.trunc())
+        }).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Evaluate the AST rooted at self without producing non-finite values.
+    /// Return an error instead of panicking on division by zero,
+    /// and instead of propagating inf or NaN from a literal or an operation.
+    pub fn evaluate_strict(&self) -> Result<f64, String> {
+        self.eval_by(DivByZero::Error, &mut |_, value| {
+            if !value.is_finite() {
+                return Err(String::from("Arithmetic overflow!"));
+            }
+            Ok(value)
+        })
+    }
+
+    /// Evaluate the AST rooted at self, recording each intermediate result.
+    /// Values appear in the order they are computed: every Number, then every
+    /// operation once both of its operands are known.
+    /// The final entry is the result of the whole computation.
+    /// Panics on division by zero, as evaluate does.
+    pub fn evaluate_steps(&self) -> Vec<f64> {
+        let mut steps = Vec::new();
+        self.eval_by(DivByZero::Error, &mut |_, value| {
+            steps.push(value);
+            Ok(value)
+        }).unwrap_or_else(|error| panic!("{}", error));
+        steps
+    }
+
+    // Shared evaluator behind every evaluation mode.
+    // Each literal and each operation result is passed through step,
+    // along with the node that produced it.
+    // step may adjust, record or reject the value before it is used.
+    // Division by zero is an error only under DivByZero::Error.
+    fn eval_by<F>(&self, div_by_zero: DivByZero, step: &mut F) -> Result<f64, String>
+    where
+        F: FnMut(&Token, f64) -> Result<f64, String>,
+    {
+        let value = match self {
             Token::Number { value } => { *value }
             Token::Add { left, right } => {
-                left.evaluate() + right.evaluate()
+                left.eval_by(div_by_zero, step)? + right.eval_by(div_by_zero, step)?
             }
             Token::Subtract { left, right } => {
-                left.evaluate() - right.evaluate()
+                left.eval_by(div_by_zero, step)? - right.eval_by(div_by_zero, step)?
             }
             Token::Multiply { left, right } => {
-                left.evaluate() * right.evaluate()
+                left.eval_by(div_by_zero, step)? * right.eval_by(div_by_zero, step)?
             }
             Token::Divide { left, right } => {
-                let (left, right) = (left.evaluate(), right.evaluate());
-                if right == 0.0 {
-                    panic!("Divide by zero!");
+                let (left, right) = (left.eval_by(div_by_zero, step)?, right.eval_by(div_by_zero, step)?);
+                if right == 0.0 && div_by_zero == DivByZero::Error {
+                    return Err(String::from("Divide by zero!"));
                 }
                 left / right
             }
+        };
+        step(self, value)
+    }
+
+    /// The operator self applies, or None for a Number.
+    pub fn operator(&self) -> Option<Operator> {
+        match self {
+            Token::Number { .. } => { None }
+            Token::Add { .. } => { Some(Operator::Add) }
+            Token::Subtract { .. } => { Some(Operator::Subtract) }
+            Token::Multiply { .. } => { Some(Operator::Multiply) }
+            Token::Divide { .. } => { Some(Operator::Divide) }
+        }
+    }
+
+    /// Number of levels in the AST rooted at self.
+    /// A lone Number has depth 1.
+    pub fn depth(&self) -> usize {
+        match self.operands() {
+            Some((left, right)) => { 1 + left.depth().max(right.depth()) }
+            None => { 1 }
+        }
+    }
+
+    /// Total number of nodes in the AST rooted at self.
+    pub fn node_count(&self) -> usize {
+        match self.operands() {
+            Some((left, right)) => { 1 + left.node_count() + right.node_count() }
+            None => { 1 }
+        }
+    }
+
+    /// Whether self and other have the same operator structure,
+    /// ignoring the values of any Numbers.
+    pub fn structurally_eq(&self, other: &Token) -> bool {
+        if std::mem::discriminant(self) != std::mem::discriminant(other) {
+            return false;
+        }
+        match (self.operands(), other.operands()) {
+            (Some((left, right)), Some((other_left, other_right))) => {
+                left.structurally_eq(other_left) && right.structurally_eq(other_right)
+            }
+            _ => { true }
+        }
+    }
+
+    /// Clone the subtree reached from self by following path.
+    /// An empty path clones the whole tree.
+    /// Return None if the path leads past a Number.
+    pub fn clone_subtree(&self, path: &[Branch]) -> Option<Token> {
+        let mut token = self;
+        for branch in path {
+            let (left, right) = token.operands()?;
+            token = match branch {
+                Branch::Left => { left }
+                Branch::Right => { right }
+            };
+        }
+        Some(token.clone())
+    }
+
+    /// Walk the AST rooted at self depth-first, left to right,
+    /// calling visitor on entry to and exit from each node.
+    pub fn walk<V: AstVisitor>(&self, visitor: &mut V) {
+        visitor.enter(self);
+        if let Some((left, right)) = self.operands() {
+            left.walk(visitor);
+            right.walk(visitor);
+        }
+        visitor.exit(self);
+    }
+
+    /// Iterate over the AST rooted at self in postorder:
+    /// each node is yielded after its left and right subtrees.
+    pub fn postorder_iter(&self) -> impl Iterator<Item = &Token> {
+        PostorderIter { stack: vec![(self, false)] }
+    }
+
+    /// Fold every constant subtree rooted at self into a single Number.
+    /// Return an error if any divisor folds to zero,
+    /// whether or not the numerator is constant,
+    /// or if a literal or folded value would not be finite, as in evaluate_strict.
+    pub fn fold_constants(self) -> Result<Token, String> {
+        let folded = match self {
+            Token::Number { .. } => {
+                self.evaluate_strict()?;
+                return Ok(self);
+            }
+            Token::Add { left, right } => {
+                Token::Add { left: Box::new(left.fold_constants()?), right: Box::new(right.fold_constants()?) }
+            }
+            Token::Subtract { left, right } => {
+                Token::Subtract { left: Box::new(left.fold_constants()?), right: Box::new(right.fold_constants()?) }
+            }
+            Token::Multiply { left, right } => {
+                Token::Multiply { left: Box::new(left.fold_constants()?), right: Box::new(right.fold_constants()?) }
+            }
+            Token::Divide { left, right } => {
+                Token::Divide { left: Box::new(left.fold_constants()?), right: Box::new(right.fold_constants()?) }
+            }
+        };
+
+        match folded.operands() {
+            Some((_, Token::Number { value: divisor }))
+                if matches!(folded, Token::Divide { .. }) && *divisor == 0.0 => {
+                Err(String::from("Division by zero in constant expression!"))
+            }
+            Some((Token::Number { .. }, Token::Number { .. })) => {
+                Ok(Token::Number { value: folded.evaluate_strict()? })
+            }
+            // Unreachable while Token has no variables, since every operand
+            // folds to a Number. Non-constant subtrees will be kept here.
+            _ => { Ok(folded) }
+        }
+    }
+
+    /// Return the value self always evaluates to, if it folds to a constant.
+    /// A tree that fold_constants rejects is not constant and yields None.
+    /// Token has no variables, so this is the strict evaluation of self,
+    /// which fails exactly where folding does, without copying the tree.
+    pub fn is_constant(&self) -> Option<f64> {
+        self.evaluate_strict().ok()
+    }
+
+    /// Serialize the AST rooted at self to JSON.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    /// Deserialize an AST from JSON produced by to_json.
+    #[cfg(feature = "json")]
+    pub fn from_json(json: &str) -> Result<Token, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    /// Serialize the AST rooted at self to compact bincode bytes.
+    #[cfg(feature = "bincode")]
+    pub fn to_bytes(&self) -> Result<Vec<u8>, bincode::Error> {
+        bincode::serialize(self)
+    }
+
+    /// Deserialize an AST from bytes produced by to_bytes.
+    /// Trees nested deeper than 128 levels are rejected before decoding,
+    /// so untrusted input cannot overflow the stack.
+    #[cfg(feature = "bincode")]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Token, bincode::Error> {
+        check_bincode_depth(bytes)?;
+        bincode::deserialize(bytes)
+    }
+
+    /// Left and right operands of self, or None for a Number.
+    fn operands(&self) -> Option<(&Token, &Token)> {
+        match self {
+            Token::Number { .. } => { None }
+            Token::Add { left, right }
+            | Token::Subtract { left, right }
+            | Token::Multiply { left, right }
+            | Token::Divide { left, right } => { Some((left, right)) }
+        }
+    }
+}
+
+// Deepest tree Token::from_bytes accepts, matching serde_json's recursion limit.
+#[cfg(feature = "bincode")]
+const MAX_BYTES_DEPTH: usize = 128;
+
+// Scan the variant tags of a bincode-encoded Token without recursing,
+// rejecting trees nested deeper than MAX_BYTES_DEPTH.
+// Each node is a little-endian u32 tag in Token's variant order:
+// Number (0) is followed by its f64, the operators (1-4) by their operands.
+// Malformed input is left for bincode to report.
+#[cfg(feature = "bincode")]
+fn check_bincode_depth(bytes: &[u8]) -> Result<(), bincode::Error> {
+    // Depths of the nodes still to be read, next node last.
+    let mut pending = vec![1];
+    let mut pos = 0;
+    while let Some(depth) = pending.pop() {
+        if depth > MAX_BYTES_DEPTH {
+            let message = format!("Tree nested deeper than {} levels!", MAX_BYTES_DEPTH);
+            return Err(Box::new(bincode::ErrorKind::Custom(message)));
+        }
+        let tag = match bytes.get(pos..pos + 4) {
+            Some(tag) => { u32::from_le_bytes([tag[0], tag[1], tag[2], tag[3]]) }
+            None => { return Ok(()) }
+        };
+        pos += 4;
+        match tag {
+            0 => { pos += 8 }
+            1..=4 => {
+                pending.push(depth + 1);
+                pending.push(depth + 1);
+            }
+            _ => { return Ok(()) }
+        }
+    }
+    Ok(())
+}
+
+// Lazy postorder traversal backing Token::postorder_iter.
+// Each stack entry records whether that node's children have been pushed.
+struct PostorderIter<'a> {
+    stack: Vec<(&'a Token, bool)>,
+}
+
+impl<'a> Iterator for PostorderIter<'a> {
+    type Item = &'a Token;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (token, expanded) = self.stack.pop()?;
+            match token.operands() {
+                Some((left, right)) if !expanded => {
+                    self.stack.push((token, true));
+                    self.stack.push((right, false));
+                    self.stack.push((left, false));
+                }
+                _ => { return Some(token) }
+            }
+        }
+    }
+}
+
+/// # Description
+/// Aggregate statistics over a corpus of programs.
+/// Ingest the AST of each program, then report on operator frequency,
+/// average tree depth, and the most common constants.
+#[derive(Debug, Default)]
+pub struct CorpusStats {
+    programs: usize,
+    total_depth: usize,
+    operators: BTreeMap<Operator, usize>,
+    // f64 is not hashable, so constants are keyed by their bit pattern.
+    // Zeros and NaNs are normalized first so each counts as one constant.
+    constants: HashMap<u64, usize>,
+}
+
+impl CorpusStats {
+    /// Create an empty accumulator.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the program rooted at ast to the corpus.
+    pub fn ingest(&mut self, ast: &Token) {
+        self.programs += 1;
+        self.total_depth += ast.depth();
+        for token in ast.postorder_iter() {
+            if let Token::Number { value } = token {
+                self.record_constant(*value);
+            } else if let Some(operator) = token.operator() {
+                *self.operators.entry(operator).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Number of times operator appears across the corpus.
+    pub fn operator_count(&self, operator: Operator) -> usize {
+        *self.operators.get(&operator).unwrap_or(&0)
+    }
+
+    /// Average depth of the ingested trees, or 0 if the corpus is empty.
+    pub fn average_depth(&self) -> f64 {
+        if self.programs == 0 {
+            return 0.0;
+        }
+        self.total_depth as f64 / self.programs as f64
+    }
+
+    /// Constants ordered by descending frequency.
+    /// Ties are broken by ascending value, so the order is stable.
+    pub fn common_constants(&self) -> Vec<(f64, usize)> {
+        let mut constants: Vec<(f64, usize)> = self.constants.iter()
+            .map(|(bits, count)| (f64::from_bits(*bits), *count))
+            .collect();
+        constants.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.total_cmp(&b.0)));
+        constants
+    }
+
+    /// Human-readable summary of the corpus.
+    pub fn report(&self) -> String {
+        let mut report = format!("Programs: {}\n", self.programs);
+        report += &format!("Average depth: {:.2}\n", self.average_depth());
+        report += "Operators:\n";
+        for (operator, count) in &self.operators {
+            report += &format!("  {:?}: {}\n", operator, count);
+        }
+        report += "Most common constants:\n";
+        for (value, count) in self.common_constants().iter().take(5) {
+            report += &format!("  {}: {}\n", value, count);
+        }
+        report
+    }
+
+    // Count one occurrence of the constant value.
+    fn record_constant(&mut self, value: f64) {
+        let value = if value == 0.0 {
+            0.0
+        } else if value.is_nan() {
+            f64::NAN
+        } else {
+            value
+        };
+        *self.constants.entry(value.to_bits()).or_insert(0) += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(value: f64) -> Box<Token> {
+        Box::new(Token::Number { value })
+    }
+
+    #[test]
+    fn test_corpus_stats() {
+        let mut stats = CorpusStats::new();
+        stats.ingest(&Token::Add { left: num(1.0), right: num(2.0) });
+        stats.ingest(&Token::Multiply {
+            left: Box::new(Token::Add { left: num(1.0), right: num(3.0) }),
+            right: num(2.0),
+        });
+        stats.ingest(&Token::Divide { left: num(4.0), right: num(1.0) });
+
+        assert_eq!(2, stats.operator_count(Operator::Add));
+        assert_eq!(1, stats.operator_count(Operator::Multiply));
+        assert_eq!(1, stats.operator_count(Operator::Divide));
+        assert_eq!(0, stats.operator_count(Operator::Subtract));
+        assert_eq!((2.0 + 3.0 + 2.0) / 3.0, stats.average_depth());
+        assert_eq!((1.0, 3), stats.common_constants()[0]);
+
+        let report = stats.report();
+        assert!(report.contains("Programs: 3\n"));
+        assert!(report.contains("  Add: 2\n"));
+        assert!(report.contains("  Multiply: 1\n"));
+        assert!(report.contains("  Divide: 1\n"));
+    }
+
+    #[test]
+    fn test_corpus_stats_normalizes_constants() {
+        let mut stats = CorpusStats::new();
+        stats.ingest(&Token::Add { left: num(0.0), right: num(-0.0) });
+        stats.ingest(&Token::Add { left: num(f64::NAN), right: num(-f64::NAN) });
+
+        let constants = stats.common_constants();
+        assert_eq!(2, constants.len());
+        assert!(constants.iter().any(|(value, count)| *value == 0.0 && *count == 2));
+        assert!(constants.iter().any(|(value, count)| value.is_nan() && *count == 2));
+    }
+
+    #[test]
+    fn test_evaluate_strict_overflow() {
+        let overflow = Token::Multiply { left: num(f64::MAX), right: num(2.0) };
+        assert_eq!(f64::INFINITY, overflow.evaluate());
+        assert_eq!(Err(String::from("Arithmetic overflow!")), overflow.evaluate_strict());
+    }
+
+    #[test]
+    fn test_evaluate_strict_non_finite_literal() {
+        assert!(Token::Number { value: f64::INFINITY }.evaluate_strict().is_err());
+        assert!(Token::Number { value: f64::NAN }.evaluate_strict().is_err());
+        assert_eq!(Ok(3.0), Token::Add { left: num(1.0), right: num(2.0) }.evaluate_strict());
+    }
+
+    #[test]
+    fn test_evaluate_strict_divide_by_zero() {
+        let divide = Token::Divide { left: num(1.0), right: num(0.0) };
+        assert_eq!(Err(String::from("Divide by zero!")), divide.evaluate_strict());
+    }
+
+    #[test]
+    fn test_evaluate_integer_division() {
+        let divide = Token::Divide { left: num(7.0), right: num(2.0) };
+        assert_eq!(3.0, divide.evaluate_integer());
+        assert_eq!(3.5, divide.evaluate());
+
+        let negative = Token::Divide { left: num(-7.0), right: num(2.0) };
+        assert_eq!(-3.0, negative.evaluate_integer());
+    }
+
+    #[test]
+    fn test_evaluate_integer_truncates_multiply() {
+        let multiply = Token::Multiply { left: num(2.5), right: num(3.9) };
+        assert_eq!(9.0, multiply.evaluate_integer());
+
+        // (7 / 2) * 2.5 = 3 * 2.5 = 7.5, truncated to 7
+        let nested = Token::Multiply {
+            left: Box::new(Token::Divide { left: num(7.0), right: num(2.0) }),
+            right: num(2.5),
+        };
+        assert_eq!(7.0, nested.evaluate_integer());
+    }
+
+    #[test]
+    fn test_evaluate_integer_fractional_divisor() {
+        assert_eq!(2.0, Token::Divide { left: num(1.0), right: num(0.5) }.evaluate_integer());
+    }
+
+    #[test]
+    #[should_panic(expected = "Divide by zero!")]
+    fn test_evaluate_integer_divide_by_zero() {
+        Token::Divide { left: num(1.0), right: num(0.0) }.evaluate_integer();
+    }
+
+    #[test]
+    fn test_div_by_zero_ieee() {
+        let divide = Token::Divide { left: num(1.0), right: num(0.0) };
+        assert_eq!(f64::INFINITY, divide.evaluate_with(DivByZero::Ieee));
+
+        let indeterminate = Token::Divide { left: num(0.0), right: num(0.0) };
+        assert!(indeterminate.evaluate_with(DivByZero::Ieee).is_nan());
+    }
+
+    #[test]
+    #[should_panic(expected = "Divide by zero!")]
+    fn test_div_by_zero_error() {
+        Token::Divide { left: num(1.0), right: num(0.0) }.evaluate_with(DivByZero::Error);
+    }
+
+    #[test]
+    #[should_panic(expected = "Divide by zero!")]
+    fn test_evaluate_div_by_zero() {
+        Token::Divide { left: num(1.0), right: num(0.0) }.evaluate();
+    }
+
+    #[test]
+    fn test_div_by_zero_default() {
+        assert_eq!(DivByZero::Error, DivByZero::default());
+    }
+
+    #[test]
+    fn test_evaluate_steps() {
+        // (3 + 5) * 2
+        let product = Token::Multiply {
+            left: Box::new(Token::Add { left: num(3.0), right: num(5.0) }),
+            right: num(2.0),
+        };
+        assert_eq!(vec![3.0, 5.0, 8.0, 2.0, 16.0], product.evaluate_steps());
+        assert_eq!(vec![4.0], Token::Number { value: 4.0 }.evaluate_steps());
+    }
+
+    #[test]
+    fn test_is_constant() {
+        // 2 * 3 + 1
+        let sum = Token::Add {
+            left: Box::new(Token::Multiply { left: num(2.0), right: num(3.0) }),
+            right: num(1.0),
+        };
+        assert_eq!(Some(7.0), sum.is_constant());
+        assert_eq!(None, Token::Divide { left: num(1.0), right: num(0.0) }.is_constant());
+    }
+
+    #[test]
+    fn test_is_constant_overflow() {
+        let overflow = Token::Multiply { left: num(f64::MAX), right: num(2.0) };
+        assert_eq!(None, overflow.is_constant());
+        assert!(overflow.clone().fold_constants().is_err());
+        assert!(overflow.evaluate_strict().is_err());
+
+        let infinity = Token::Number { value: f64::INFINITY };
+        assert_eq!(None, infinity.is_constant());
+        assert!(infinity.fold_constants().is_err());
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        // (2 * 3) + (10 / 4)
+        let sum = Token::Add {
+            left: Box::new(Token::Multiply { left: num(2.0), right: num(3.0) }),
+            right: Box::new(Token::Divide { left: num(10.0), right: num(4.0) }),
+        };
+        let folded = sum.clone().fold_constants().unwrap();
+
+        assert_eq!(sum.evaluate(), folded.evaluate());
+        assert_eq!(Token::Number { value: 8.5 }, folded);
+    }
+
+    #[test]
+    fn test_fold_constants_number() {
+        let number = Token::Number { value: 4.0 };
+        assert_eq!(Ok(number.clone()), number.fold_constants());
+    }
+
+    #[test]
+    fn test_fold_constants_divide_by_zero() {
+        let error = Err(String::from("Division by zero in constant expression!"));
+        assert_eq!(error, Token::Divide { left: num(1.0), right: num(0.0) }.fold_constants());
+
+        // Token has no variables, so a compound numerator stands in for a
+        // non-literal one. The check only looks at the divisor.
+        let divide = Token::Divide {
+            left: Box::new(Token::Add {
+                left: Box::new(Token::Multiply { left: num(2.0), right: num(3.0) }),
+                right: num(4.0),
+            }),
+            right: Box::new(Token::Subtract { left: num(1.0), right: num(1.0) }),
+        };
+        assert_eq!(error, divide.fold_constants());
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn test_json_round_trip() {
+        let product = Token::Multiply {
+            left: Box::new(Token::Add { left: num(3.0), right: num(5.0) }),
+            right: num(2.0),
+        };
+        let json = product.to_json().unwrap();
+        assert_eq!(product, Token::from_json(&json).unwrap());
+        assert!(Token::from_json("{\"Number\": {}}").is_err());
+    }
+
+    #[cfg(feature = "bincode")]
+    fn nested_tree() -> Token {
+        // (3 + 1 / 4) * (2 - 8)
+        Token::Multiply {
+            left: Box::new(Token::Add {
+                left: num(3.0),
+                right: Box::new(Token::Divide { left: num(1.0), right: num(4.0) }),
+            }),
+            right: Box::new(Token::Subtract { left: num(2.0), right: num(8.0) }),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "bincode")]
+    fn test_bincode_round_trip() {
+        let tree = nested_tree();
+        let bytes = tree.to_bytes().unwrap();
+        assert_eq!(tree, Token::from_bytes(&bytes).unwrap());
+        assert!(Token::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    // Left-leaning chain of Adds with the given depth.
+    #[cfg(feature = "bincode")]
+    fn chain(depth: usize) -> Token {
+        let mut tree = Token::Number { value: 1.0 };
+        for _ in 1..depth {
+            tree = Token::Add { left: Box::new(tree), right: num(1.0) };
+        }
+        tree
+    }
+
+    #[test]
+    #[cfg(feature = "bincode")]
+    fn test_bincode_depth_limit() {
+        let deepest = chain(128);
+        assert_eq!(deepest, Token::from_bytes(&deepest.to_bytes().unwrap()).unwrap());
+        assert!(Token::from_bytes(&chain(129).to_bytes().unwrap()).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "bincode")]
+    fn test_bincode_deeply_nested_input() {
+        // A million Add tags with no operands would recurse a million
+        // levels deep in bincode without the depth check.
+        let bytes: Vec<u8> = [1u8, 0, 0, 0].repeat(1_000_000);
+        assert!(Token::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature = "json", feature = "bincode"))]
+    fn test_bincode_smaller_than_json() {
+        let tree = nested_tree();
+        assert!(tree.to_bytes().unwrap().len() < tree.to_json().unwrap().len());
+    }
+
+    // Short label for a node, used to compare traversal orders.
+    fn label(token: &Token) -> String {
+        match token {
+            Token::Number { value } => { value.to_string() }
+            Token::Add { .. } => { String::from("+") }
+            Token::Subtract { .. } => { String::from("-") }
+            Token::Multiply { .. } => { String::from("*") }
+            Token::Divide { .. } => { String::from("/") }
+        }
+    }
+
+    // Records every enter and exit hook in the order they fire.
+    struct Recorder {
+        events: Vec<String>,
+    }
+
+    impl AstVisitor for Recorder {
+        fn enter(&mut self, token: &Token) {
+            self.events.push(format!("enter {}", label(token)));
+        }
+
+        fn exit(&mut self, token: &Token) {
+            self.events.push(format!("exit {}", label(token)));
+        }
+    }
+
+    #[test]
+    fn test_walk() {
+        // (1 + 2) * 3
+        let product = Token::Multiply {
+            left: Box::new(Token::Add { left: num(1.0), right: num(2.0) }),
+            right: num(3.0),
+        };
+        let mut recorder = Recorder { events: Vec::new() };
+        product.walk(&mut recorder);
+
+        assert_eq!(vec![
+            "enter *", "enter +", "enter 1", "exit 1", "enter 2", "exit 2", "exit +",
+            "enter 3", "exit 3", "exit *",
+        ], recorder.events);
+    }
+
+    #[test]
+    fn test_postorder_iter() {
+        // (1 - 2) * (3 / 4)
+        let product = Token::Multiply {
+            left: Box::new(Token::Subtract { left: num(1.0), right: num(2.0) }),
+            right: Box::new(Token::Divide { left: num(3.0), right: num(4.0) }),
+        };
+        let order: Vec<String> = product.postorder_iter().map(label).collect();
+        assert_eq!(vec!["1", "2", "-", "3", "4", "/", "*"], order);
+
+        let mut recorder = Recorder { events: Vec::new() };
+        product.walk(&mut recorder);
+        let exits: Vec<String> = recorder.events.iter()
+            .filter_map(|event| event.strip_prefix("exit "))
+            .map(String::from)
+            .collect();
+        assert_eq!(exits, order);
+
+        let numbers = product.postorder_iter()
+            .filter(|token| matches!(token, Token::Number { .. }))
+            .count();
+        assert_eq!(4, numbers);
+    }
+
+    #[test]
+    fn test_depth_and_node_count() {
+        let number = Token::Number { value: 1.0 };
+        assert_eq!(1, number.depth());
+        assert_eq!(1, number.node_count());
+
+        let sum = Token::Add { left: num(1.0), right: num(2.0) };
+        assert_eq!(2, sum.depth());
+        assert_eq!(3, sum.node_count());
+
+        // 1 - (2 * (3 / 4)): a right-leaning chain.
+        let chain = Token::Subtract {
+            left: num(1.0),
+            right: Box::new(Token::Multiply {
+                left: num(2.0),
+                right: Box::new(Token::Divide { left: num(3.0), right: num(4.0) }),
+            }),
+        };
+        assert_eq!(4, chain.depth());
+        assert_eq!(7, chain.node_count());
+    }
+
+    #[test]
+    fn test_structurally_eq() {
+        let sum = Token::Add { left: num(1.0), right: num(2.0) };
+        assert!(sum.structurally_eq(&Token::Add { left: num(9.0), right: num(9.0) }));
+        assert!(!sum.structurally_eq(&Token::Multiply { left: num(1.0), right: num(2.0) }));
+        assert!(!sum.structurally_eq(&Token::Number { value: 3.0 }));
+        assert!(!sum.structurally_eq(&Token::Add { left: num(1.0), right: Box::new(sum.clone()) }));
+    }
+
+    #[test]
+    fn test_clone_is_deep() {
+        let original = Token::Add {
+            left: Box::new(Token::Multiply { left: num(2.0), right: num(3.0) }),
+            right: num(1.0),
+        };
+        let mut copy = original.clone();
+        assert_eq!(original, copy);
+
+        if let Token::Add { left, .. } = &mut copy {
+            if let Token::Multiply { right, .. } = left.as_mut() {
+                **right = Token::Number { value: 10.0 };
+            }
         }
+        assert_eq!(7.0, original.evaluate());
+        assert_eq!(21.0, copy.evaluate());
+    }
+
+    #[test]
+    fn test_clone_subtree() {
+        let original = Token::Add {
+            left: Box::new(Token::Multiply { left: num(2.0), right: num(3.0) }),
+            right: num(1.0),
+        };
+
+        let mut product = original.clone_subtree(&[Branch::Left]).unwrap();
+        assert_eq!(Token::Multiply { left: num(2.0), right: num(3.0) }, product);
+        if let Token::Multiply { left, .. } = &mut product {
+            **left = Token::Number { value: 5.0 };
+        }
+        assert_eq!(15.0, product.evaluate());
+        assert_eq!(7.0, original.evaluate());
+
+        assert_eq!(Some(Token::Number { value: 3.0 }), original.clone_subtree(&[Branch::Left, Branch::Right]));
+        assert_eq!(Some(original.clone()), original.clone_subtree(&[]));
+        assert_eq!(None, original.clone_subtree(&[Branch::Right, Branch::Left]));
+    }
+
+    #[test]
+    fn test_left_associative_subtract() {
+        // 10 - 3 - 2, built left-associatively as (10 - 3) - 2
+        let difference = Token::Subtract {
+            left: Box::new(Token::Subtract { left: num(10.0), right: num(3.0) }),
+            right: num(2.0),
+        };
+        assert_eq!(5.0, difference.evaluate());
+    }
+
+    #[test]
+    fn test_left_associative_divide() {
+        // 16 / 4 / 2, built left-associatively as (16 / 4) / 2
+        let quotient = Token::Divide {
+            left: Box::new(Token::Divide { left: num(16.0), right: num(4.0) }),
+            right: num(2.0),
+        };
+        assert_eq!(2.0, quotient.evaluate());
+    }
+
+    #[test]
+    fn test_fold_constants_non_finite() {
+        let overflow = Err(String::from("Arithmetic overflow!"));
+        assert_eq!(overflow, Token::Number { value: f64::INFINITY }.fold_constants());
+        assert_eq!(overflow, Token::Add { left: num(f64::INFINITY), right: num(0.0) }.fold_constants());
+        assert!(Token::Number { value: f64::NAN }.fold_constants().is_err());
     }
 }